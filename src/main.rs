@@ -1,17 +1,200 @@
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    routing::post,
+    Router,
+};
 use bytes::Bytes;
 use lazy_static::lazy_static;
-use log::{error, info};
+use log::{error, info, warn};
 use regex::Regex;
+use serde::Deserialize;
 use serde_json::{json, Value};
-use std::{io::Write, time::Duration};
+use std::{
+    collections::HashMap,
+    fmt,
+    future::Future,
+    io::Write,
+    path::PathBuf,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::{mpsc, oneshot, Mutex, Semaphore};
 
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync + 'static>>;
 
+/// Update kinds the bot subscribes to, shared by the poller and the webhook
+/// registration so both stay in sync.
+const ALLOWED_UPDATES: [&str; 4] = ["message", "edited_message", "callback_query", "inline_query"];
+
+/// How many times a single Telegram call is attempted before giving up.
+const MAX_ATTEMPTS: u32 = 5;
+
+/// Files at or above this size (bytes, as reported by `getFile`) are relayed as
+/// a stream instead of being buffered whole in memory.
+const STREAM_THRESHOLD: u64 = 10 * 1024 * 1024;
+
+/// The `parameters` object Telegram attaches to some errors (flood control,
+/// chat migration, ...).
+#[derive(Debug, Default, Deserialize)]
+struct TelegramErrorParameters {
+    #[serde(default)]
+    retry_after: Option<u64>,
+    // Surfaced for callers that need to follow a chat migration; not consulted
+    // by the client itself yet.
+    #[allow(dead_code)]
+    #[serde(default)]
+    migrate_to_chat_id: Option<i64>,
+}
+
+/// A non-`ok` reply from the Telegram Bot API, deserialized from the error
+/// body so callers can react to `error_code` / `retry_after` instead of
+/// pattern-matching on a string.
+#[derive(Debug, Deserialize)]
+struct TelegramError {
+    error_code: i64,
+    description: String,
+    #[serde(default)]
+    parameters: Option<TelegramErrorParameters>,
+}
+
+impl TelegramError {
+    /// Seconds Telegram asked us to wait before retrying, if any.
+    fn retry_after(&self) -> Option<u64> {
+        self.parameters.as_ref().and_then(|p| p.retry_after)
+    }
+}
+
+impl fmt::Display for TelegramError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Telegram call returned error {}: {}",
+            self.error_code, self.description
+        )
+    }
+}
+
+impl std::error::Error for TelegramError {}
+
+/// A file to hand to a `send*` method. `FileId` and `Url` are resolved by
+/// Telegram server-side (no upload), while `Upload` and `Path` are sent as
+/// multipart parts.
+enum InputFile {
+    Upload {
+        data: Bytes,
+        file_name: String,
+        mime: String,
+    },
+    // Part of the reusable media layer; `FileId` and `Upload` are the variants
+    // the bot constructs today.
+    #[allow(dead_code)]
+    Path(PathBuf),
+    #[allow(dead_code)]
+    Url(String),
+    FileId(String),
+}
+
+/// An [`InputFile`] reduced to the shape the multipart form needs. Resolving up
+/// front (reading a `Path`, classifying a `Url`/`FileId`) means the retry
+/// closure in [`TelegramClient::request_to_json`] can cheaply rebuild the form
+/// on each attempt.
+enum ResolvedInput {
+    /// Sent as a plain text field (a `file_id` or URL Telegram fetches itself).
+    Text(String),
+    /// Sent as an uploaded multipart part.
+    Bytes {
+        data: Bytes,
+        file_name: String,
+        mime: String,
+    },
+}
+
+impl InputFile {
+    async fn resolve(self) -> Result<ResolvedInput> {
+        Ok(match self {
+            InputFile::Upload {
+                data,
+                file_name,
+                mime,
+            } => ResolvedInput::Bytes {
+                data,
+                file_name,
+                mime,
+            },
+            InputFile::Path(path) => {
+                let file_name = path
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .unwrap_or("file")
+                    .to_string();
+                let mime = mime_from_extension(&path).to_string();
+                let data = Bytes::from(tokio::fs::read(&path).await?);
+                ResolvedInput::Bytes {
+                    data,
+                    file_name,
+                    mime,
+                }
+            }
+            InputFile::Url(url) => ResolvedInput::Text(url),
+            InputFile::FileId(file_id) => ResolvedInput::Text(file_id),
+        })
+    }
+}
+
+impl ResolvedInput {
+    fn attach(
+        &self,
+        field: &str,
+        form: reqwest::multipart::Form,
+    ) -> Result<reqwest::multipart::Form> {
+        Ok(match self {
+            ResolvedInput::Text(value) => form.text(field.to_string(), value.clone()),
+            ResolvedInput::Bytes {
+                data,
+                file_name,
+                mime,
+            } => {
+                let part = reqwest::multipart::Part::bytes(data.to_vec())
+                    .file_name(file_name.clone())
+                    .mime_str(mime)?;
+                form.part(field.to_string(), part)
+            }
+        })
+    }
+}
+
+/// Best-effort MIME type inferred from a path's extension, falling back to a
+/// generic binary type for anything we do not recognise.
+fn mime_from_extension(path: &std::path::Path) -> &'static str {
+    match path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_ascii_lowercase())
+        .as_deref()
+    {
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("png") => "image/png",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("mp4") => "video/mp4",
+        Some("mp3") => "audio/mpeg",
+        Some("ogg") | Some("oga") => "audio/ogg",
+        Some("pdf") => "application/pdf",
+        Some("txt") => "text/plain",
+        Some("zip") => "application/zip",
+        _ => "application/octet-stream",
+    }
+}
+
 #[derive(Clone)]
 struct TelegramClient {
     token: String,
     http: reqwest::Client,
-    offset: i64,
 }
 
 impl TelegramClient {
@@ -24,7 +207,6 @@ impl TelegramClient {
                 .connection_verbose(true)
                 .build()
                 .expect("Failed to create http client"),
-            offset: 0,
         }
     }
 
@@ -32,103 +214,850 @@ impl TelegramClient {
         format!("https://api.telegram.org/bot{}/{}", self.token, method)
     }
 
-    async fn request_to_json(&self, request: reqwest::RequestBuilder) -> Result<Value> {
-        let response = request
+    /// Issue a request and decode the `ok`/`result` envelope, retrying on
+    /// flood control (429) and transient server errors (5xx).
+    ///
+    /// `build` is invoked once per attempt because a `RequestBuilder` (and in
+    /// particular a multipart body) is consumed on `send`, so it cannot be
+    /// cloned between retries.
+    async fn request_to_json<F>(&self, build: F) -> Result<Value>
+    where
+        F: Fn() -> Result<reqwest::RequestBuilder>,
+    {
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            // A build error (e.g. a caller-supplied invalid MIME) is not
+            // transient, so surface it immediately rather than retrying.
+            let request = build()?;
+            let response = match request.send().await {
+                Ok(response) => response,
+                Err(err) => {
+                    // Network-level failures (timeouts, resets) are worth a few
+                    // retries before we give up on the update.
+                    if attempt < MAX_ATTEMPTS && (err.is_timeout() || err.is_connect()) {
+                        let backoff = exponential_backoff(attempt);
+                        warn!("Request failed ({}), retrying in {:?}", err, backoff);
+                        tokio::time::sleep(backoff).await;
+                        continue;
+                    }
+                    return Err(err.into());
+                }
+            };
+            let status = response.status();
+            // A transient server error may arrive as a non-JSON body (e.g. a
+            // reverse proxy's 502/504 HTML page), so retry on the status before
+            // attempting to decode the envelope.
+            if attempt < MAX_ATTEMPTS && status.is_server_error() {
+                let backoff = exponential_backoff(attempt);
+                warn!("Server error {}, retrying in {:?}", status, backoff);
+                tokio::time::sleep(backoff).await;
+                continue;
+            }
+            let mut body = match response.json::<Value>().await? {
+                Value::Object(value) => value,
+                _ => panic!(),
+            };
+            if body["ok"].as_bool().unwrap() {
+                return Ok(body.remove("result").unwrap());
+            }
+
+            let error: TelegramError =
+                serde_json::from_value(Value::Object(body.clone()))?;
+            let is_flood = status.as_u16() == 429 || error.error_code == 429;
+            let is_transient = status.is_server_error() || error.error_code >= 500;
+            if attempt < MAX_ATTEMPTS && (is_flood || is_transient) {
+                let delay = if is_flood {
+                    // Honour `retry_after` when present, falling back to the
+                    // exponential schedule otherwise.
+                    match error.retry_after() {
+                        Some(secs) => Duration::from_secs(secs) + jitter(),
+                        None => exponential_backoff(attempt),
+                    }
+                } else {
+                    exponential_backoff(attempt)
+                };
+                warn!("{}, retrying in {:?}", error, delay);
+                tokio::time::sleep(delay).await;
+                continue;
+            }
+            return Err(error.into());
+        }
+    }
+
+    async fn call_method(&self, method: &str, params: &Value) -> Result<Value> {
+        let url = self.build_url(method);
+        let timeout = Duration::from_secs(if method == "getUpdates" { 90 } else { 10 });
+        self.request_to_json(|| Ok(self.http.post(&url).timeout(timeout).json(params)))
+            .await
+    }
+
+    /// Fetch a batch at the given offset. The offset is *not* advanced here:
+    /// the caller moves it forward only past updates it has actually handled,
+    /// so an un-handled update is re-fetched rather than silently confirmed to
+    /// Telegram.
+    async fn get_updates(&self, offset: i64) -> Result<Value> {
+        self.call_method(
+            "getUpdates",
+            &json!({
+                "offset": offset,
+                "timeout": 60,
+                "allowed_updates": ALLOWED_UPDATES,
+            }),
+        )
+        .await
+    }
+
+    async fn send_message(
+        &self,
+        chat_id: i64,
+        text: &str,
+        reply_to: Option<i64>,
+    ) -> Result<Value> {
+        let mut params = json!({ "chat_id": chat_id, "text": text });
+        if let Some(reply_to) = reply_to {
+            params["reply_to_message_id"] = json!(reply_to);
+        }
+        self.call_method("sendMessage", &params).await
+    }
+
+    async fn set_webhook(&self, url: &str, secret_token: &str) -> Result<Value> {
+        self.call_method(
+            "setWebhook",
+            &json!({
+                "url": url,
+                "secret_token": secret_token,
+                "allowed_updates": ALLOWED_UPDATES,
+            }),
+        )
+        .await
+    }
+
+    async fn delete_webhook(&self) -> Result<Value> {
+        self.call_method("deleteWebhook", &json!({})).await
+    }
+
+    /// Resolve a `file_id` to its download path and size (the latter is absent
+    /// for some file types).
+    async fn get_file_meta(&self, file_id: String) -> Result<(String, Option<u64>)> {
+        let result = self
+            .call_method("getFile", &json!({ "file_id": file_id }))
+            .await?;
+        let file_path = result["file_path"].as_str().unwrap().to_string();
+        let file_size = result["file_size"].as_u64();
+        Ok((file_path, file_size))
+    }
+
+    fn file_url(&self, file_path: &str) -> String {
+        format!(
+            "https://api.telegram.org/file/bot{}/{}",
+            self.token, file_path
+        )
+    }
+
+    /// Download a file fully into memory. Suitable for small payloads.
+    async fn download_file(&self, file_path: &str) -> Result<Bytes> {
+        let response = self
+            .http
+            .get(self.file_url(file_path))
             .send()
             .await?
-            .error_for_status()?
-            .json::<Value>()
-            .await?;
-        let mut response = match response {
+            .error_for_status()?;
+        Ok(response.bytes().await?)
+    }
+
+    /// Open a file download as a streaming response whose body can be piped
+    /// straight into a multipart upload without buffering.
+    async fn download_stream(&self, file_path: &str) -> Result<reqwest::Response> {
+        let response = self
+            .http
+            .get(self.file_url(file_path))
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(response)
+    }
+
+    /// Send a request once and decode the envelope, without the retry wrapper.
+    /// Used for streamed uploads, whose bodies cannot be replayed.
+    async fn send_once(&self, request: reqwest::RequestBuilder) -> Result<Value> {
+        let mut body = match request.send().await?.json::<Value>().await? {
             Value::Object(value) => value,
             _ => panic!(),
         };
-        if response["ok"].as_bool().unwrap() {
-            Ok(response.remove("result").unwrap())
+        if body["ok"].as_bool().unwrap() {
+            Ok(body.remove("result").unwrap())
         } else {
-            Err(format!("Telegram call returned error: {:?}", response).into())
+            let error: TelegramError = serde_json::from_value(Value::Object(body))?;
+            Err(error.into())
         }
     }
 
-    async fn call_method(&self, method: &str, params: &Value) -> Result<Value> {
-        let request = self
-            .http
-            .post(self.build_url(method))
-            .timeout(Duration::from_secs(if method == "getUpdates" {
-                90
-            } else {
-                10
-            }))
-            .json(params);
-        self.request_to_json(request).await
+    /// Shared implementation behind every `send*` media method: resolve the
+    /// inputs once, then (re)build the multipart form per attempt.
+    async fn send_media(
+        &self,
+        method: &str,
+        field: &str,
+        file: InputFile,
+        chat_id: i64,
+        caption: Option<&str>,
+        reply_to: Option<i64>,
+        thumb: Option<InputFile>,
+    ) -> Result<Value> {
+        let media = file.resolve().await?;
+        let thumb = match thumb {
+            Some(thumb) => Some(thumb.resolve().await?),
+            None => None,
+        };
+        let url = self.build_url(method);
+        let field = field.to_string();
+        let caption = caption.map(|caption| caption.to_string());
+        self.request_to_json(|| {
+            let mut form =
+                reqwest::multipart::Form::new().text("chat_id", chat_id.to_string());
+            form = media.attach(&field, form)?;
+            if let Some(thumb) = &thumb {
+                form = thumb.attach("thumb", form)?;
+            }
+            if let Some(caption) = &caption {
+                form = form.text("caption", caption.clone());
+            }
+            if let Some(reply_to) = reply_to {
+                form = form.text("reply_to_message_id", reply_to.to_string());
+            }
+            Ok(self.http.post(&url).multipart(form))
+        })
+        .await
     }
 
-    async fn get_updates(&mut self) -> Result<Value> {
-        let response = self
-            .call_method(
-                "getUpdates",
-                &json!({
-                    "offset": self.offset,
-                    "timeout": 60,
-                    "allowed_updates": ["message"],
-                }),
+    /// Stream a download straight into an upload, so bytes flow from Telegram's
+    /// file endpoint back to `send*` without the whole payload sitting in RAM.
+    ///
+    /// A consumed stream body cannot be replayed, so unlike [`Self::send_media`]
+    /// this cannot lean on [`Self::request_to_json`]'s retry wrapper. It does
+    /// honour flood control (and typed 5xx) by re-opening the download from
+    /// `file_path` and replaying the whole upload; other failures surface
+    /// immediately.
+    async fn send_media_stream(
+        &self,
+        method: &str,
+        field: &str,
+        file_path: &str,
+        file_name: &str,
+        mime: &str,
+        chat_id: i64,
+        caption: Option<&str>,
+        reply_to: Option<i64>,
+    ) -> Result<Value> {
+        let url = self.build_url(method);
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            // Re-open the download each attempt: the previous attempt consumed
+            // its stream, so a retry needs a fresh source from the file endpoint.
+            let response = self.download_stream(file_path).await?;
+            let part = reqwest::multipart::Part::stream(reqwest::Body::wrap_stream(
+                response.bytes_stream(),
+            ))
+            .file_name(file_name.to_string())
+            .mime_str(mime)?;
+            let mut form = reqwest::multipart::Form::new()
+                .text("chat_id", chat_id.to_string())
+                .part(field.to_string(), part);
+            if let Some(caption) = caption {
+                form = form.text("caption", caption.to_string());
+            }
+            if let Some(reply_to) = reply_to {
+                form = form.text("reply_to_message_id", reply_to.to_string());
+            }
+            match self
+                .send_once(self.http.post(&url).multipart(form))
+                .await
+            {
+                Ok(value) => return Ok(value),
+                Err(error) => {
+                    // Only Telegram-reported flood control / server errors are
+                    // safely retryable here, since reopening the download lets
+                    // us replay the upload from scratch.
+                    if attempt < MAX_ATTEMPTS {
+                        if let Some(telegram) = error.downcast_ref::<TelegramError>() {
+                            if telegram.error_code == 429 || telegram.error_code >= 500 {
+                                let delay = match telegram.retry_after() {
+                                    Some(secs) => Duration::from_secs(secs) + jitter(),
+                                    None => exponential_backoff(attempt),
+                                };
+                                warn!("{}, retrying streamed upload in {:?}", telegram, delay);
+                                tokio::time::sleep(delay).await;
+                                continue;
+                            }
+                        }
+                    }
+                    return Err(error);
+                }
+            }
+        }
+    }
+
+    async fn send_photo(
+        &self,
+        photo: InputFile,
+        chat_id: i64,
+        caption: Option<&str>,
+        reply_to: Option<i64>,
+    ) -> Result<Value> {
+        self.send_media("sendPhoto", "photo", photo, chat_id, caption, reply_to, None)
+            .await
+    }
+
+    async fn send_document(
+        &self,
+        document: InputFile,
+        chat_id: i64,
+        caption: Option<&str>,
+        reply_to: Option<i64>,
+        thumb: Option<InputFile>,
+    ) -> Result<Value> {
+        self.send_media(
+            "sendDocument",
+            "document",
+            document,
+            chat_id,
+            caption,
+            reply_to,
+            thumb,
+        )
+        .await
+    }
+
+    #[allow(dead_code)]
+    async fn send_audio(
+        &self,
+        audio: InputFile,
+        chat_id: i64,
+        caption: Option<&str>,
+        reply_to: Option<i64>,
+    ) -> Result<Value> {
+        self.send_media("sendAudio", "audio", audio, chat_id, caption, reply_to, None)
+            .await
+    }
+
+    #[allow(dead_code)]
+    async fn send_video(
+        &self,
+        video: InputFile,
+        chat_id: i64,
+        caption: Option<&str>,
+        reply_to: Option<i64>,
+        thumb: Option<InputFile>,
+    ) -> Result<Value> {
+        self.send_media("sendVideo", "video", video, chat_id, caption, reply_to, thumb)
+            .await
+    }
+}
+
+/// Exponentially growing delay (2^attempt seconds) used when Telegram does not
+/// tell us how long to wait, capped so a pathological outage cannot stall the
+/// bot indefinitely.
+fn exponential_backoff(attempt: u32) -> Duration {
+    let secs = 2u64.saturating_pow(attempt).min(60);
+    Duration::from_secs(secs) + jitter()
+}
+
+/// A little randomness (< 1s) so retries from many chats do not all fire at the
+/// exact same instant.
+fn jitter() -> Duration {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or(0);
+    Duration::from_millis((nanos % 1000) as u64)
+}
+
+/// A route matches an incoming update by its kind. Commands additionally match
+/// on the command name (with any `@botname` suffix stripped).
+enum Route {
+    Command(String),
+    Text,
+    Photo,
+    Document,
+    EditedMessage,
+    CallbackQuery,
+    InlineQuery,
+}
+
+/// The features of an update the dispatcher needs to pick a route, extracted
+/// once per update.
+#[derive(Default)]
+struct UpdateInfo {
+    chat_id: Option<i64>,
+    user_id: Option<i64>,
+    command: Option<String>,
+    args: Vec<String>,
+    text: Option<String>,
+    has_photo: bool,
+    has_document: bool,
+    is_edited: bool,
+    is_callback: bool,
+    is_inline: bool,
+}
+
+impl UpdateInfo {
+    fn from_update(update: &Value) -> Self {
+        let mut info = UpdateInfo::default();
+        if let Some(callback_query) = update.get("callback_query") {
+            info.is_callback = true;
+            info.user_id = callback_query["from"]["id"].as_i64();
+            info.chat_id = callback_query["message"]["chat"]["id"].as_i64();
+            return info;
+        }
+        if let Some(inline_query) = update.get("inline_query") {
+            info.is_inline = true;
+            info.user_id = inline_query["from"]["id"].as_i64();
+            return info;
+        }
+        let message = if let Some(message) = update.get("edited_message") {
+            info.is_edited = true;
+            message
+        } else if let Some(message) = update.get("message") {
+            message
+        } else {
+            return info;
+        };
+        info.chat_id = message["chat"]["id"].as_i64();
+        info.user_id = message["from"]["id"].as_i64();
+        info.has_photo = matches!(message.get("photo"), Some(Value::Array(_)));
+        info.has_document = message.get("document").is_some();
+        if let Some(text) = message.get("text").and_then(|text| text.as_str()) {
+            info.text = Some(text.to_string());
+            if text.starts_with('/') {
+                let mut parts = text.split_whitespace();
+                if let Some(first) = parts.next() {
+                    let name = first.split('@').next().unwrap_or(first);
+                    info.command = Some(name.to_string());
+                    info.args = parts.map(|part| part.to_string()).collect();
+                }
+            }
+        }
+        info
+    }
+}
+
+impl Route {
+    fn matches(&self, info: &UpdateInfo) -> bool {
+        match self {
+            Route::Command(name) => {
+                !info.is_edited && info.command.as_deref() == Some(name.as_str())
+            }
+            Route::Text => !info.is_edited && info.command.is_none() && info.text.is_some(),
+            Route::Photo => !info.is_edited && info.has_photo,
+            Route::Document => !info.is_edited && info.has_document,
+            Route::EditedMessage => info.is_edited,
+            Route::CallbackQuery => info.is_callback,
+            Route::InlineQuery => info.is_inline,
+        }
+    }
+}
+
+/// Everything a handler needs: the client to talk back, the chat/user ids, the
+/// parsed command and arguments (for command routes), and the raw update for
+/// anything not surfaced above.
+struct Context {
+    telegram_client: TelegramClient,
+    chat_id: Option<i64>,
+    user_id: Option<i64>,
+    command: Option<String>,
+    args: Vec<String>,
+    text: Option<String>,
+    update: Value,
+}
+
+type HandlerFuture = Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+type Handler = Box<dyn Fn(Context) -> HandlerFuture + Send + Sync>;
+
+/// Routing layer that replaces the old single `if let`: updates are matched
+/// against registered routes in registration order and dispatched to the first
+/// handler that matches.
+struct Dispatcher {
+    telegram_client: TelegramClient,
+    routes: Vec<(Route, Handler)>,
+}
+
+impl Dispatcher {
+    fn new(telegram_client: TelegramClient) -> Self {
+        Dispatcher {
+            telegram_client,
+            routes: Vec::new(),
+        }
+    }
+
+    fn on<F, Fut>(&mut self, route: Route, handler: F) -> &mut Self
+    where
+        F: Fn(Context) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        self.routes
+            .push((route, Box::new(move |ctx| Box::pin(handler(ctx)))));
+        self
+    }
+
+    async fn dispatch(&self, update: Value) -> Result<()> {
+        info!("Processing update: {}", update);
+        let info = UpdateInfo::from_update(&update);
+        for (route, handler) in &self.routes {
+            if route.matches(&info) {
+                let context = Context {
+                    telegram_client: self.telegram_client.clone(),
+                    chat_id: info.chat_id,
+                    user_id: info.user_id,
+                    command: info.command,
+                    args: info.args,
+                    text: info.text,
+                    update,
+                };
+                return handler(context).await;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Build the dispatcher with the bot's default routes.
+fn build_dispatcher(telegram_client: TelegramClient) -> Dispatcher {
+    let mut dispatcher = Dispatcher::new(telegram_client);
+    dispatcher
+        .on(Route::Command("/start".to_string()), handle_start)
+        .on(Route::Command("/help".to_string()), handle_help)
+        .on(Route::Command("/echo".to_string()), handle_echo)
+        .on(Route::Photo, handle_photo)
+        .on(Route::Document, handle_document)
+        .on(Route::EditedMessage, handle_edited_message)
+        .on(Route::CallbackQuery, handle_callback_query)
+        .on(Route::InlineQuery, handle_inline_query)
+        .on(Route::Text, handle_text);
+    dispatcher
+}
+
+async fn handle_start(context: Context) -> Result<()> {
+    if let Some(chat_id) = context.chat_id {
+        context
+            .telegram_client
+            .send_message(
+                chat_id,
+                "Hi! Send me a photo and I'll echo it right back.",
+                None,
+            )
+            .await?;
+    }
+    Ok(())
+}
+
+async fn handle_help(context: Context) -> Result<()> {
+    if let Some(chat_id) = context.chat_id {
+        context
+            .telegram_client
+            .send_message(
+                chat_id,
+                "Send a photo to have it echoed. Commands: /start, /help.",
+                None,
             )
             .await?;
-        if let Some(last_update) = response.as_array().unwrap().last() {
-            self.offset = last_update["update_id"].as_i64().unwrap() + 1;
+    }
+    Ok(())
+}
+
+/// Echo back the command's arguments, exercising the slash-command argument
+/// parsing the dispatcher performs.
+async fn handle_echo(context: Context) -> Result<()> {
+    if let Some(chat_id) = context.chat_id {
+        let command = context.command.as_deref().unwrap_or("/echo");
+        let reply = if context.args.is_empty() {
+            format!("Usage: {} <text to echo>", command)
+        } else {
+            context.args.join(" ")
         };
-        Ok(response)
+        context
+            .telegram_client
+            .send_message(chat_id, &reply, None)
+            .await?;
     }
+    Ok(())
+}
 
-    async fn get_file(&self, file_id: String) -> Result<Bytes> {
-        let get_file_result = self
-            .call_method("getFile", &json!({ "file_id": file_id }))
+/// Echo any plain (non-command) text message back to the chat.
+async fn handle_text(context: Context) -> Result<()> {
+    if let (Some(chat_id), Some(text)) = (context.chat_id, context.text.as_deref()) {
+        context
+            .telegram_client
+            .send_message(chat_id, text, None)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Relay a document straight back by its `file_id`, so Telegram serves it from
+/// its own storage without a re-upload.
+async fn handle_document(context: Context) -> Result<()> {
+    let message = &context.update["message"];
+    if let (Some(chat_id), Some(file_id)) = (
+        context.chat_id,
+        message["document"]["file_id"].as_str(),
+    ) {
+        let reply_to = message["message_id"].as_i64();
+        context
+            .telegram_client
+            .send_document(
+                InputFile::FileId(file_id.to_string()),
+                chat_id,
+                None,
+                reply_to,
+                None,
+            )
             .await?;
-        let file_path = get_file_result.as_object().unwrap()["file_path"]
+    }
+    Ok(())
+}
+
+/// Acknowledge an edited message without re-processing it as a fresh one.
+async fn handle_edited_message(context: Context) -> Result<()> {
+    if let Some(chat_id) = context.chat_id {
+        let text = context.text.as_deref().unwrap_or("");
+        info!("Ignoring edit in chat {}: {:?}", chat_id, text);
+    }
+    Ok(())
+}
+
+/// Placeholder for callback queries (inline keyboard button presses); logged
+/// until a concrete behaviour is wired up.
+async fn handle_callback_query(context: Context) -> Result<()> {
+    info!("Received callback query from user {:?}", context.user_id);
+    Ok(())
+}
+
+/// Placeholder for inline queries; logged until a concrete behaviour is wired
+/// up.
+async fn handle_inline_query(context: Context) -> Result<()> {
+    info!("Received inline query from user {:?}", context.user_id);
+    Ok(())
+}
+
+async fn handle_photo(context: Context) -> Result<()> {
+    let message = &context.update["message"];
+    if let Value::Array(ref sizes) = message["photo"] {
+        let chat_id = message["chat"]["id"].as_i64().unwrap();
+        let reply_to = message["message_id"].as_i64().unwrap();
+        let file_id = sizes.last().unwrap().as_object().unwrap()["file_id"]
             .as_str()
             .unwrap();
-        let request = self.http.get(format!(
-            "https://api.telegram.org/file/bot{}/{}",
-            self.token, file_path
-        ));
-        let bytes = request.send().await?.error_for_status()?.bytes().await?;
-        Ok(bytes)
+        let (file_path, file_size) = context.telegram_client.get_file_meta(file_id.into()).await?;
+        if file_size.is_some_and(|size| size >= STREAM_THRESHOLD) {
+            // Large file: relay it as a stream so neither download nor upload
+            // buffers the whole payload.
+            context
+                .telegram_client
+                .send_media_stream(
+                    "sendPhoto",
+                    "photo",
+                    &file_path,
+                    "image.jpg",
+                    "image/jpeg",
+                    chat_id,
+                    None,
+                    Some(reply_to),
+                )
+                .await?;
+        } else {
+            let file = context.telegram_client.download_file(&file_path).await?;
+            let photo = InputFile::Upload {
+                data: file,
+                file_name: "image.jpg".to_string(),
+                mime: "image/jpeg".to_string(),
+            };
+            context
+                .telegram_client
+                .send_photo(photo, chat_id, None, Some(reply_to))
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Persists the `getUpdates` offset to a file so a restart resumes where the
+/// last committed batch left off. The offset is committed only after a batch
+/// has been processed, giving at-least-once delivery across crashes.
+struct OffsetStore {
+    path: PathBuf,
+}
+
+impl OffsetStore {
+    /// Load the stored offset, creating the file (seeded with `0`) if it does
+    /// not exist yet.
+    fn load_or_create(path: PathBuf) -> Result<(Self, i64)> {
+        let offset = match std::fs::read_to_string(&path) {
+            Ok(contents) => contents.trim().parse().unwrap_or(0),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+                std::fs::write(&path, "0")?;
+                0
+            }
+            Err(error) => return Err(error.into()),
+        };
+        Ok((OffsetStore { path }, offset))
     }
 
-    async fn send_photo(&self, photo: Bytes, chat_id: i64, reply_to: i64) -> Result<Value> {
-        let data_part = reqwest::multipart::Part::bytes(photo.to_vec())
-            .file_name("image.jpg")
-            .mime_str("image/jpeg")?;
-        let form = reqwest::multipart::Form::new()
-            .text("chat_id", chat_id.to_string())
-            .text("reply_to_message_id", reply_to.to_string())
-            .part("photo", data_part);
-        let request = self.http.post(self.build_url("sendPhoto")).multipart(form);
-        self.request_to_json(request).await
+    fn commit(&self, offset: i64) -> Result<()> {
+        std::fs::write(&self.path, offset.to_string())?;
+        Ok(())
     }
 }
 
-async fn process_update(update: Value, telegram_client: TelegramClient) -> Result<()> {
-    info!("Processing update: {}", update);
-    if let Some(message) = update.get("message") {
-        if let Value::Array(ref sizes) = message["photo"] {
-            let chat_id = message["chat"]["id"].as_i64().unwrap();
-            let reply_to = message["message_id"].as_i64().unwrap();
-            let file_id = sizes.last().unwrap().as_object().unwrap()["file_id"]
-                .as_str()
-                .unwrap();
-            let file = telegram_client.get_file(file_id.into()).await?;
-            telegram_client.send_photo(file, chat_id, reply_to).await?;
+/// An update queued for a worker alongside a signal fired once it has been
+/// processed. The payload reports whether the handler succeeded, so the poll
+/// loop only advances the offset past updates that were actually handled.
+type WorkItem = (Value, oneshot::Sender<bool>);
+
+/// A per-chat serialized worker: the queue feeding its task, the last time it
+/// was handed an update, and a count of updates queued-or-in-flight so an idle
+/// worker is only reaped once it has truly drained.
+struct ChatWorker {
+    sender: mpsc::UnboundedSender<WorkItem>,
+    last_used: Instant,
+    outstanding: Arc<AtomicUsize>,
+}
+
+/// How long a per-chat worker may sit idle before it is evicted, so serving
+/// many short-lived chats does not leak a task + channel per chat forever.
+const CHAT_WORKER_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Schedules updates for processing with two guarantees: a global
+/// [`Semaphore`] caps how many updates run at once, and updates for a given
+/// `chat_id` are funnelled through a dedicated serialized worker so messages
+/// within one conversation are never reordered. Updates with no chat affinity
+/// (e.g. inline queries) are dispatched directly under a permit.
+struct UpdateScheduler {
+    dispatcher: Arc<Dispatcher>,
+    semaphore: Arc<Semaphore>,
+    chats: HashMap<i64, ChatWorker>,
+}
+
+impl UpdateScheduler {
+    fn new(dispatcher: Arc<Dispatcher>, max_concurrent: usize) -> Self {
+        UpdateScheduler {
+            dispatcher,
+            semaphore: Arc::new(Semaphore::new(max_concurrent)),
+            chats: HashMap::new(),
         }
     }
-    Ok(())
+
+    /// Spawn a worker that processes one chat's updates strictly in order, each
+    /// under a global permit so per-chat serialization never bypasses the
+    /// concurrency cap.
+    fn spawn_chat_worker(
+        dispatcher: Arc<Dispatcher>,
+        semaphore: Arc<Semaphore>,
+        outstanding: Arc<AtomicUsize>,
+    ) -> mpsc::UnboundedSender<WorkItem> {
+        let (sender, mut receiver) = mpsc::unbounded_channel::<WorkItem>();
+        tokio::spawn(async move {
+            while let Some((update, done)) = receiver.recv().await {
+                let _permit = semaphore.clone().acquire_owned().await.unwrap();
+                let outcome = dispatcher.dispatch(update).await;
+                if let Err(error) = &outcome {
+                    error!("{}", error);
+                }
+                let _ = done.send(outcome.is_ok());
+                // Decrement only after the item is fully handled, so a worker
+                // with anything queued or in flight is never seen as idle.
+                outstanding.fetch_sub(1, Ordering::SeqCst);
+            }
+        });
+        sender
+    }
+
+    /// Drop workers that have been idle past the timeout *and* have no update
+    /// queued or in flight. Gating on the drained count prevents spawning a
+    /// second worker for a chat whose single dispatch simply ran longer than the
+    /// timeout, which would let the two run concurrently and reorder the
+    /// conversation. Dropping the sender lets the (idle) worker exit cleanly
+    /// when `recv()` returns `None`.
+    fn evict_idle(&mut self) {
+        let now = Instant::now();
+        self.chats.retain(|_, worker| {
+            worker.outstanding.load(Ordering::SeqCst) > 0
+                || now.duration_since(worker.last_used) < CHAT_WORKER_IDLE_TIMEOUT
+        });
+    }
+
+    /// Queue an update and return a receiver that fires once it has been
+    /// processed, carrying `true` if the handler succeeded.
+    fn schedule(&mut self, update: Value) -> oneshot::Receiver<bool> {
+        let (done, completion) = oneshot::channel();
+        let chat_id = UpdateInfo::from_update(&update).chat_id;
+        match chat_id {
+            Some(chat_id) => {
+                self.evict_idle();
+                let dispatcher = self.dispatcher.clone();
+                let semaphore = self.semaphore.clone();
+                let worker = self.chats.entry(chat_id).or_insert_with(|| {
+                    let outstanding = Arc::new(AtomicUsize::new(0));
+                    ChatWorker {
+                        sender: Self::spawn_chat_worker(dispatcher, semaphore, outstanding.clone()),
+                        last_used: Instant::now(),
+                        outstanding,
+                    }
+                });
+                worker.last_used = Instant::now();
+                // Count the item before handing it over, so the worker is never
+                // reaped while this update is still queued or in flight.
+                worker.outstanding.fetch_add(1, Ordering::SeqCst);
+                // A live worker only refuses a send once its task is gone (a
+                // panic); drop the stale entry and retry with a fresh worker
+                // (the failed send hands the item back).
+                if let Err(send_error) = worker.sender.send((update, done)) {
+                    let outstanding = Arc::new(AtomicUsize::new(1));
+                    let sender = Self::spawn_chat_worker(
+                        self.dispatcher.clone(),
+                        self.semaphore.clone(),
+                        outstanding.clone(),
+                    );
+                    let _ = sender.send(send_error.0);
+                    self.chats.insert(
+                        chat_id,
+                        ChatWorker {
+                            sender,
+                            last_used: Instant::now(),
+                            outstanding,
+                        },
+                    );
+                }
+            }
+            None => {
+                let dispatcher = self.dispatcher.clone();
+                let semaphore = self.semaphore.clone();
+                tokio::spawn(async move {
+                    let _permit = semaphore.acquire_owned().await.unwrap();
+                    let outcome = dispatcher.dispatch(update).await;
+                    if let Err(error) = &outcome {
+                        error!("{}", error);
+                    }
+                    let _ = done.send(outcome.is_ok());
+                });
+            }
+        }
+        completion
+    }
 }
 
 async fn listen() -> ! {
-    let mut telegram_client = TelegramClient::new();
+    let telegram_client = TelegramClient::new();
+    let offset_path = std::env::var("OFFSET_FILE").unwrap_or_else(|_| "offset.state".to_string());
+    let (offset_store, mut offset) =
+        OffsetStore::load_or_create(offset_path.into()).expect("Failed to open offset store");
+    info!("Resuming from offset {}", offset);
+    let dispatcher = Arc::new(build_dispatcher(telegram_client.clone()));
+    let mut scheduler = UpdateScheduler::new(dispatcher, max_concurrent_updates());
     loop {
         let updates = {
-            let updates = telegram_client.get_updates().await;
+            let updates = telegram_client.get_updates(offset).await;
             if let Err(error) = updates {
                 error!("{}", error);
                 continue;
@@ -139,18 +1068,141 @@ async fn listen() -> ! {
                 panic!()
             }
         };
-        let joins: Vec<_> = updates
-            .into_iter()
-            .map(|update| tokio::spawn(process_update(update, telegram_client.clone())))
-            .collect();
-        for join in joins {
-            if let Err(error) = join.await.unwrap() {
-                error!("{}", error);
+        let mut completions = Vec::with_capacity(updates.len());
+        let mut next_offset = None;
+        for update in updates {
+            let update_id = update["update_id"].as_i64();
+            if let Some(update_id) = update_id {
+                next_offset = Some(update_id + 1);
+            }
+            let completion = scheduler.schedule(update);
+            if let Some(update_id) = update_id {
+                completions.push((update_id, completion));
+            }
+        }
+        // Wait for the whole batch, then advance only past the contiguous prefix
+        // of updates that were handled successfully: the first update whose
+        // handler failed (or panicked, dropping its sender) is left uncommitted
+        // so it is re-fetched, giving at-least-once processing.
+        let mut first_failure = None;
+        for (update_id, completion) in completions {
+            let handled = completion.await.unwrap_or(false);
+            if !handled {
+                first_failure =
+                    Some(first_failure.map_or(update_id, |existing: i64| existing.min(update_id)));
+            }
+        }
+        // Drive the next poll from the committed value: on a clean batch advance
+        // past it, otherwise rewind to the failed update so it (and everything
+        // after it) is re-fetched instead of being confirmed to Telegram. An
+        // earlier failure is never leapfrogged because `offset` only moves
+        // forward once that update has actually been handled.
+        let next = match (first_failure, next_offset) {
+            (Some(failed), _) => Some(failed),
+            (None, Some(next_offset)) => Some(next_offset),
+            (None, None) => None,
+        };
+        if let Some(next) = next {
+            offset = next;
+            if let Err(error) = offset_store.commit(next) {
+                error!("Failed to persist offset: {}", error);
             }
         }
     }
 }
 
+/// Maximum number of updates processed concurrently, shared by both intake
+/// modes so the concurrency cap does not depend on how updates arrive.
+fn max_concurrent_updates() -> usize {
+    std::env::var("MAX_CONCURRENT_UPDATES")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(16)
+}
+
+/// Shared state handed to the webhook route. Updates go through the same
+/// [`UpdateScheduler`] as polling so webhook deployments get identical bounded
+/// concurrency and per-chat ordering.
+struct WebhookState {
+    scheduler: Mutex<UpdateScheduler>,
+    secret_token: String,
+}
+
+/// Receive updates over an HTTP webhook instead of long-polling. Telegram POSTs
+/// each update to a secret path and echoes our secret token in the
+/// `X-Telegram-Bot-Api-Secret-Token` header; both are validated before an
+/// update is fed into the same [`UpdateScheduler`] used by polling.
+async fn listen_webhook() -> ! {
+    let telegram_client = TelegramClient::new();
+    let public_url = std::env::var("WEBHOOK_URL").expect("Unable to get WEBHOOK_URL from env");
+    let path_token =
+        std::env::var("WEBHOOK_PATH_TOKEN").expect("Unable to get WEBHOOK_PATH_TOKEN from env");
+    let secret_token =
+        std::env::var("WEBHOOK_SECRET_TOKEN").expect("Unable to get WEBHOOK_SECRET_TOKEN from env");
+    let bind = std::env::var("WEBHOOK_BIND").unwrap_or_else(|_| "0.0.0.0:8443".to_string());
+
+    let webhook_url = format!("{}/{}", public_url.trim_end_matches('/'), path_token);
+    telegram_client
+        .set_webhook(&webhook_url, &secret_token)
+        .await
+        .expect("Failed to register webhook");
+    info!("Webhook registered at {}", webhook_url);
+
+    let dispatcher = Arc::new(build_dispatcher(telegram_client.clone()));
+    let scheduler = UpdateScheduler::new(dispatcher, max_concurrent_updates());
+    let state = Arc::new(WebhookState {
+        scheduler: Mutex::new(scheduler),
+        secret_token,
+    });
+    let app = Router::new()
+        .route(&format!("/{}", path_token), post(handle_webhook))
+        .with_state(state);
+
+    let listener = tokio::net::TcpListener::bind(&bind)
+        .await
+        .expect("Failed to bind webhook listener");
+    info!("Listening for webhooks on {}", bind);
+    let server = axum::serve(listener, app).with_graceful_shutdown(async {
+        tokio::signal::ctrl_c().await.ok();
+    });
+    if let Err(error) = server.await {
+        error!("Webhook server error: {}", error);
+    }
+
+    // Best-effort cleanup so a restart in polling mode is not shadowed by a
+    // stale webhook registration.
+    if let Err(error) = telegram_client.delete_webhook().await {
+        error!("Failed to delete webhook: {}", error);
+    }
+    std::process::exit(0);
+}
+
+async fn handle_webhook(
+    State(state): State<Arc<WebhookState>>,
+    headers: HeaderMap,
+    body: String,
+) -> StatusCode {
+    let provided = headers
+        .get("X-Telegram-Bot-Api-Secret-Token")
+        .and_then(|value| value.to_str().ok());
+    if provided != Some(state.secret_token.as_str()) {
+        warn!("Rejected webhook request with invalid secret token");
+        return StatusCode::UNAUTHORIZED;
+    }
+    let update: Value = match serde_json::from_str(&body) {
+        Ok(update) => update,
+        Err(error) => {
+            error!("Failed to parse webhook update: {}", error);
+            return StatusCode::BAD_REQUEST;
+        }
+    };
+    // Hand the update to the scheduler and return immediately; it enforces the
+    // concurrency cap and per-chat ordering, and the completion signal is not
+    // needed here because the webhook has no offset to commit.
+    state.scheduler.lock().await.schedule(update);
+    StatusCode::OK
+}
+
 fn format_path(
     path: &str,
     line: u32,
@@ -195,5 +1247,9 @@ async fn main() {
         .filter(None, log::LevelFilter::Trace)
         .init();
 
-    tokio::spawn(listen()).await.unwrap();
+    let mode = std::env::var("BOT_MODE").unwrap_or_else(|_| "polling".to_string());
+    match mode.as_str() {
+        "webhook" => tokio::spawn(listen_webhook()).await.unwrap(),
+        _ => tokio::spawn(listen()).await.unwrap(),
+    }
 }